@@ -1,4 +1,4 @@
-use std::{collections::HashMap, env, io::Write};
+use std::{collections::{HashMap, HashSet}, env, io::Write};
 use std::process;
 use std::str;
 use std::io;
@@ -216,18 +216,21 @@ async fn run_cmd(cmd_str: &str, old_vars: HashMap<String, String>, multiline: bo
     let mut errorcode = String::from("");
     let mut check_next_exit_code = false;
     let mut check_cd = false;
+    let mut new_keys: HashSet<String> = HashSet::new();
+    let mut env_done = false;
     while let Some(line) = reader.next_line().await.unwrap() {
         // found end of output, so write a new command to input
         if line.ends_with("<EOF>Exit>>") {
             // echo errorlevel and env variables on new input line in order to avoid messing up original command
-            writer.write_all(b"echo %errorlevel% & echo %CD% & set & exit\n").await.unwrap();
+            // <EOF>Env>> marks the end of the set output (escaped so cmd doesn't redirect)
+            writer.write_all(b"echo %errorlevel% & echo %CD% & set & echo ^<EOF^>Env^>^> & exit\n").await.unwrap();
             writer.flush().await.unwrap();
             marker = true;
         // we found a marker, this is the env section
         } else if marker {
             if line != "" && !line.starts_with("PROMPT=") {
                 // this is one of the lines since we \n'd it
-                if line.ends_with("echo %errorlevel% & echo %CD% & set & exit") {
+                if line.ends_with("echo %errorlevel% & echo %CD% & set & echo ^<EOF^>Env^>^> & exit") {
                     check_next_exit_code = true;
                     continue;
                 } else if check_next_exit_code {
@@ -240,9 +243,15 @@ async fn run_cmd(cmd_str: &str, old_vars: HashMap<String, String>, multiline: bo
                     check_cd = false;
                     env::set_current_dir(line).unwrap_or(());
                     continue;
+                } else if line.trim() == "<EOF>Env>>" {
+                    // no = in it, so it can't be one of the set lines
+                    env_done = true;
+                    continue;
                 }
 
                 if let Some((k, v)) = line.splitn(2, "=").collect_tuple() {
+                    // env var names are case insensitive on Windows
+                    new_keys.insert(k.to_lowercase());
                     // new key or changed value for existing key
                     if !old_vars.contains_key(k) || old_vars.get(k).unwrap() != v {
                         env::set_var(k, v);
@@ -261,6 +270,17 @@ async fn run_cmd(cmd_str: &str, old_vars: HashMap<String, String>, multiline: bo
         }
     }
 
+    // remove vars that were deleted in the child
+    // only when the whole env section was read, otherwise we'd wipe everything
+    if env_done {
+        for k in old_vars.keys() {
+            let key = k.to_lowercase();
+            // =C:, =ExitCode etc. are hidden from set, so they'd always look deleted
+            if key != "prompt" && !key.starts_with('=') && !new_keys.contains(&key) {
+                env::remove_var(k);
+            }
+        }
+    }
 
     if multiline {
         println!("");